@@ -2,7 +2,7 @@
 
 use core::cell::Cell;
 use core::marker::Unpin;
-use core::ops::{Deref, Drop, Generator, GeneratorState};
+use core::ops::{Deref, DerefMut, Drop, Generator, GeneratorState};
 use core::option::Option;
 use core::pin::Pin;
 use core::ptr::NonNull;
@@ -11,50 +11,171 @@ use core::task::{Context, Poll};
 #[doc(inline)]
 pub use core::future::*;
 
+// Re-export `IntoFuture` by name, in addition to the glob above: downstream
+// crates using us as `core` need `.await` on an `IntoFuture` type to resolve
+// the same way it does against genuine `core`.
+#[doc(inline)]
+pub use core::future::IntoFuture;
+
 /// Wrap a generator in a future.
 ///
 /// This function returns a `GenFuture` underneath, but hides it in `impl Trait` to give
 /// better error messages (`impl Future` rather than `GenFuture<[closure.....]>`).
+///
+/// On compilers that still lower `.await` through `poll_with_tls_context`, the
+/// context is threaded through a thread-local (see the `legacy-tls` feature).
+/// On current compilers, `.await` passes the context directly as the
+/// generator's resume argument, via [`ResumeTy`]/[`get_context`].
+///
+/// `const` because the compiler requires `from_generator` to be callable
+/// from the `const fn`s it generates while recovering from `const async fn`
+/// errors.
+///
+/// # Limitation
+///
+/// On the nightlies that lower `.await` this way, rustc's HIR lowering finds
+/// `from_generator`/`ResumeTy`/`get_context` via `#[lang = "..."]` items, not
+/// via the `::core::future` path used for `poll_with_tls_context`. This crate
+/// can't tag them as such: it's `#![no_std]` (not `#![no_core]`) and itself
+/// reexports the genuine `core`, so a downstream crate using the
+/// `core = { package = "core-futures-tls" }` trick ends up with *both* the
+/// real `core` and this crate claiming the same lang items, which rustc
+/// rejects outright (duplicate lang item). That would only work with
+/// `#![no_core]` plus reimplementing every other lang item `core` provides,
+/// which is out of scope here. As written, these three items are a
+/// hand-callable surface with the right shape for callers that build their
+/// own generator-backed futures directly, but the compiler will not wire
+/// ordinary `.await` through them on those nightlies.
+#[cfg(not(feature = "legacy-tls"))]
+#[doc(hidden)]
+pub const fn from_generator<T: Generator<ResumeTy, Yield = ()>>(x: T) -> impl Future<Output = T::Return> {
+    GenFuture(x)
+}
+
+#[cfg(feature = "legacy-tls")]
 #[doc(hidden)]
-pub fn from_generator<T: Generator<Yield = ()>>(x: T) -> impl Future<Output = T::Return> {
+pub const fn from_generator<T: Generator<Yield = ()>>(x: T) -> impl Future<Output = T::Return> {
     GenFuture(x)
 }
 
 /// A wrapper around generators used to implement `Future` for `async`/`await` code.
 #[doc(hidden)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
-struct GenFuture<T: Generator<Yield = ()>>(T);
+struct GenFuture<T>(T);
 
 // We rely on the fact that async/await futures are immovable in order to create
 // self-referential borrows in the underlying generator.
-impl<T: Generator<Yield = ()>> !Unpin for GenFuture<T> {}
+impl<T> !Unpin for GenFuture<T> {}
 
+#[cfg(not(feature = "legacy-tls"))]
+#[doc(hidden)]
+impl<T: Generator<ResumeTy, Yield = ()>> Future for GenFuture<T> {
+    type Output = T::Return;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safe because we're !Unpin + !Drop mapping to a ?Unpin value
+        let gen = unsafe { Pin::map_unchecked_mut(self, |s| &mut s.0) };
+        // `cx` is passed directly as the resume argument, so the generator
+        // recovers it at each await point through `get_context` without
+        // touching any thread-local state.
+        let cx_ptr = NonNull::from(unsafe { core::mem::transmute::<&mut Context<'_>, &mut Context<'static>>(cx) });
+        match gen.resume(ResumeTy(cx_ptr)) {
+            GeneratorState::Yielded(()) => Poll::Pending,
+            GeneratorState::Complete(x) => Poll::Ready(x),
+        }
+    }
+}
+
+#[cfg(feature = "legacy-tls")]
 #[doc(hidden)]
 impl<T: Generator<Yield = ()>> Future for GenFuture<T> {
     type Output = T::Return;
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         // Safe because we're !Unpin + !Drop mapping to a ?Unpin value
         let gen = unsafe { Pin::map_unchecked_mut(self, |s| &mut s.0) };
-        set_task_context(cx, || match gen.resume() {
+        // Safety: `_guard` is dropped at the end of this scope, which is before
+        // `cx` goes out of scope in the caller, so TLS never outlives the
+        // context it points at.
+        let _guard = unsafe { set_task_context(cx) };
+        match gen.resume() {
             GeneratorState::Yielded(()) => Poll::Pending,
             GeneratorState::Complete(x) => Poll::Ready(x),
-        })
+        }
     }
 }
 
+/// The resume argument threaded through `async`/`await` generators on
+/// compilers that pass the task context directly, instead of stashing it in
+/// TLS. [`get_context`] recovers the `&mut Context` from this value. This
+/// crate's `ResumeTy` is not a compiler lang item, so ordinary `.await` does
+/// not route through it (see [`from_generator`]); it is produced only by
+/// `GenFuture::poll`.
+///
+/// The generator moves this value across yield points (and potentially
+/// across threads along with the rest of the generator's state), so it must
+/// be `Send`/`Sync` even though the pointer it carries is not itself
+/// thread-safe; the actual safety requirement is upheld by `GenFuture::poll`,
+/// which only ever hands out the pointer for the duration of a single poll.
+#[cfg(not(feature = "legacy-tls"))]
+#[doc(hidden)]
+pub struct ResumeTy(NonNull<Context<'static>>);
+
+#[cfg(not(feature = "legacy-tls"))]
+unsafe impl Send for ResumeTy {}
+#[cfg(not(feature = "legacy-tls"))]
+unsafe impl Sync for ResumeTy {}
+
+#[cfg(not(feature = "legacy-tls"))]
+#[doc(hidden)]
+/// Recovers the task context from the resume argument passed to an
+/// `async`/`await` generator at an await point.
+///
+/// # Safety
+///
+/// `cx` must be the `ResumeTy` the generator received from `GenFuture::poll`
+/// as its resume argument for the poll in progress: the returned lifetimes
+/// `'a`/`'b` are unconstrained by the signature, so a caller producing a
+/// `ResumeTy` from anything else can manufacture an unbounded `&mut Context`.
+pub unsafe fn get_context<'a, 'b>(cx: ResumeTy) -> &'a mut Context<'b> {
+    &mut *cx.0.as_ptr()
+}
+
+// The TLS path stores the context behind a small backend abstraction rather
+// than a single hardcoded storage mechanism, since `#[thread_local]` (ELF
+// TLS) isn't available everywhere: SGX enclaves and many bare-metal/RTOS
+// targets have no ELF TLS support. `set_task_context`/`get_task_context` are
+// written purely against `ContextSlot`; which implementation backs `CX_SLOT`
+// below is chosen at compile time by feature flag.
+#[cfg(feature = "legacy-tls")]
+trait ContextSlot {
+    /// Swaps `v` into the slot, returning the value that was previously there.
+    fn replace(&self, v: Option<NonNull<Context<'static>>>) -> Option<NonNull<Context<'static>>>;
+}
+
+/// Default backend: the (unstable) `#[thread_local]` attribute, i.e. ELF TLS.
+/// Not available on targets without OS/loader support for it.
+#[cfg(feature = "legacy-tls")]
+#[cfg(not(any(feature = "unsafe-single-thread", feature = "embedder-context")))]
 #[thread_local]
-#[cfg(not(feature = "unsafe-single-thread"))]
-static TLS_CX: Cell<Option<NonNull<Context<'static>>>> = Cell::new(None);
-#[cfg(feature = "unsafe-single-thread")]
-static TLS_CX: SingleCore<Cell<Option<NonNull<Context<'static>>>>> = SingleCore(Cell::new(None));
+static CX_SLOT: Cell<Option<NonNull<Context<'static>>>> = Cell::new(None);
+
+#[cfg(feature = "legacy-tls")]
+#[cfg(not(any(feature = "unsafe-single-thread", feature = "embedder-context")))]
+impl ContextSlot for Cell<Option<NonNull<Context<'static>>>> {
+    fn replace(&self, v: Option<NonNull<Context<'static>>>) -> Option<NonNull<Context<'static>>> {
+        Cell::replace(self, v)
+    }
+}
 
 // A wrapper which derefs to T and is always Sync. This is completely unsound, but is "safe"
 // because we only use this when the user activates the 'unsafe-single-thread' feature to indicate
 // that the program will only ever be run on a single core.
+#[cfg(feature = "legacy-tls")]
 struct SingleCore<T>(T);
 
+#[cfg(feature = "legacy-tls")]
 unsafe impl<T> Sync for SingleCore<T> {}
 
+#[cfg(feature = "legacy-tls")]
 impl<T> Deref for SingleCore<T> {
     type Target = T;
 
@@ -63,62 +184,169 @@ impl<T> Deref for SingleCore<T> {
     }
 }
 
+/// Single-core backend: a plain global behind `SingleCore`. Wildly unsafe in
+/// general (it's a `Sync` global with no real synchronization), but "safe" on
+/// targets the embedder guarantees will only ever run on one core.
+#[cfg(feature = "legacy-tls")]
+#[cfg(all(feature = "unsafe-single-thread", not(feature = "embedder-context")))]
+static CX_SLOT: SingleCore<Cell<Option<NonNull<Context<'static>>>>> = SingleCore(Cell::new(None));
+
+#[cfg(feature = "legacy-tls")]
+#[cfg(all(feature = "unsafe-single-thread", not(feature = "embedder-context")))]
+impl ContextSlot for SingleCore<Cell<Option<NonNull<Context<'static>>>>> {
+    fn replace(&self, v: Option<NonNull<Context<'static>>>) -> Option<NonNull<Context<'static>>> {
+        self.0.replace(v)
+    }
+}
+
+/// Embedder-provided backend: the downstream crate supplies these two hooks
+/// and maps them onto whatever per-thread (or per-task) storage its runtime
+/// already has, e.g. an SGX enclave's TCS-local data or an RTOS's per-task
+/// control block. Intended for targets that have neither ELF TLS nor the
+/// single-core guarantee `unsafe-single-thread` requires.
+#[cfg(feature = "legacy-tls")]
+#[cfg(feature = "embedder-context")]
+extern "Rust" {
+    fn __core_futures_tls_get() -> Option<NonNull<Context<'static>>>;
+    fn __core_futures_tls_set(v: Option<NonNull<Context<'static>>>);
+}
+
+#[cfg(feature = "legacy-tls")]
+#[cfg(feature = "embedder-context")]
+struct EmbedderSlot;
+
+#[cfg(feature = "legacy-tls")]
+#[cfg(feature = "embedder-context")]
+static CX_SLOT: EmbedderSlot = EmbedderSlot;
+
+#[cfg(feature = "legacy-tls")]
+#[cfg(feature = "embedder-context")]
+impl ContextSlot for EmbedderSlot {
+    fn replace(&self, v: Option<NonNull<Context<'static>>>) -> Option<NonNull<Context<'static>>> {
+        // Safety: the embedder is responsible for making these hooks behave
+        // like a single per-thread (or per-task) slot, same contract as the
+        // ELF-TLS and single-core backends above.
+        unsafe {
+            let old = __core_futures_tls_get();
+            __core_futures_tls_set(v);
+            old
+        }
+    }
+}
+
+/// RAII guard returned by [`set_task_context`] that restores the previous
+/// TLS context when dropped.
+///
+/// # Invariant
+///
+/// The caller must ensure this guard is dropped before the `&mut Context`
+/// that was passed to `set_task_context` is dropped, and before any guard
+/// returned by an outer call to `set_task_context`/`get_task_context` is
+/// dropped. Violating this ordering lets `CX_SLOT` dangle.
+#[cfg(feature = "legacy-tls")]
+#[doc(hidden)]
 struct SetOnDrop(Option<NonNull<Context<'static>>>);
 
+#[cfg(feature = "legacy-tls")]
 impl Drop for SetOnDrop {
     fn drop(&mut self) {
-        TLS_CX.set(self.0.take());
+        CX_SLOT.replace(self.0.take());
     }
 }
 
+#[cfg(feature = "legacy-tls")]
 #[doc(hidden)]
 /// Sets the thread-local task context used by async/await futures.
-pub fn set_task_context<F, R>(cx: &mut Context<'_>, f: F) -> R
-where
-    F: FnOnce() -> R,
-{
+///
+/// Returns a guard which restores the previous TLS value when dropped. The
+/// guard must be dropped before `cx` itself is dropped and before any
+/// outer-scoped guard drops, so that the TLS slot never outlives the
+/// context it points at.
+///
+/// # Safety
+///
+/// The caller must uphold the drop ordering documented above; `cx`'s
+/// lifetime is erased to `'static` to allow it to be stored in TLS.
+pub unsafe fn set_task_context(cx: &mut Context<'_>) -> SetOnDrop {
     // transmute the context's lifetime to 'static so we can store it.
-    let cx = unsafe { core::mem::transmute::<&mut Context<'_>, &mut Context<'static>>(cx) };
-    let old_cx = TLS_CX.replace(Some(NonNull::from(cx)));
-    let _reset = SetOnDrop(old_cx);
-    f()
+    let cx = core::mem::transmute::<&mut Context<'_>, &mut Context<'static>>(cx);
+    let old_cx = CX_SLOT.replace(Some(NonNull::from(cx)));
+    SetOnDrop(old_cx)
 }
 
+/// RAII guard returned by [`get_task_context`] that restores the previous
+/// TLS value when dropped, and derefs to the context it's holding. The
+/// `&mut Context` is never handed out on its own: it can only be reached
+/// through the guard's `Deref`/`DerefMut`,
+/// so it can't outlive the guard that's responsible for restoring TLS.
+#[cfg(feature = "legacy-tls")]
+#[doc(hidden)]
+struct TaskContextGuard {
+    // Value to restore into `CX_SLOT` on drop (the context that was there
+    // before this guard cleared the slot).
+    restore: Option<NonNull<Context<'static>>>,
+    cx: NonNull<Context<'static>>,
+}
+
+#[cfg(feature = "legacy-tls")]
+impl Drop for TaskContextGuard {
+    fn drop(&mut self) {
+        CX_SLOT.replace(self.restore.take());
+    }
+}
+
+#[cfg(feature = "legacy-tls")]
+impl Deref for TaskContextGuard {
+    type Target = Context<'static>;
+
+    fn deref(&self) -> &Context<'static> {
+        // Safety: we hold exclusive access to the context for as long as
+        // this guard exists, since `get_task_context` cleared it out of
+        // `CX_SLOT` when creating the guard.
+        unsafe { self.cx.as_ref() }
+    }
+}
+
+#[cfg(feature = "legacy-tls")]
+impl DerefMut for TaskContextGuard {
+    fn deref_mut(&mut self) -> &mut Context<'static> {
+        // Safety: see `deref`.
+        unsafe { self.cx.as_mut() }
+    }
+}
+
+#[cfg(feature = "legacy-tls")]
 #[doc(hidden)]
 /// Retrieves the thread-local task context used by async/await futures.
 ///
-/// This function acquires exclusive access to the task context.
+/// Returns a guard which derefs to the context and restores the previous
+/// TLS value when dropped.
 ///
 /// Panics if no context has been set or if the context has already been
 /// retrieved by a surrounding call to get_task_context.
-pub fn get_task_context<F, R>(f: F) -> R
-where
-    F: FnOnce(&mut Context<'_>) -> R,
-{
-    // Clear the entry so that nested `get_task_waker` calls
+pub fn get_task_context() -> impl DerefMut<Target = Context<'static>> {
+    // Clear the entry so that nested `get_task_context` calls
     // will fail or set their own value.
-    let cx_ptr = TLS_CX.replace(None);
-    let _reset = SetOnDrop(cx_ptr);
+    let cx_ptr = CX_SLOT.replace(None);
 
-    let mut cx_ptr = cx_ptr.expect(
+    let cx = cx_ptr.expect(
         "TLS Context not set. This is a rustc bug. \
          Please file an issue on https://github.com/rust-lang/rust.",
     );
 
-    // Safety: we've ensured exclusive access to the context by
-    // removing the pointer from TLS, only to be replaced once
-    // we're done with it.
-    //
-    // The pointer that was inserted came from an `&mut Context<'_>`,
-    // so it is safe to treat as mutable.
-    unsafe { f(cx_ptr.as_mut()) }
+    TaskContextGuard {
+        restore: Some(cx),
+        cx,
+    }
 }
 
+#[cfg(feature = "legacy-tls")]
 #[doc(hidden)]
 /// Polls a future in the current thread-local task waker.
 pub fn poll_with_tls_context<F>(f: Pin<&mut F>) -> Poll<F::Output>
 where
     F: Future,
 {
-    get_task_context(|cx| F::poll(f, cx))
+    let mut guard = get_task_context();
+    F::poll(f, &mut *guard)
 }
@@ -27,13 +27,25 @@
 //! being executed. When polling a future, this task will get retrieved in order
 //! to call the future's `poll` function.
 //!
-//! 
-//! As mentioned, the libstd version of those functions use a thread-local
-//! variable, which is only supported in rust's libstd through the
-//! `thread_local!` macro - which doesn't exist in libcore. There is, however,
-//! an alternative: The (unstable) `#[thread_local]` attribute, which uses ELF
-//! TLS. Note that ELF TLS is not portable to all targets - it needs to be
-//! supported by the OS, the loader, etc...
+//! On newer nightlies, `.await` stopped lowering through
+//! `poll_with_tls_context` altogether: the generated generator now takes a
+//! resume argument (`future::ResumeTy`) carrying the context directly, and
+//! recovers it at each await point through `future::get_context`, without
+//! touching any thread-local state. This crate implements both schemes side
+//! by side (see "Context storage" below) so it can track whichever lowering
+//! the toolchain in use expects, but the `ResumeTy`/`get_context` path relies
+//! on compiler lang items that collide with the real `core`'s when this
+//! crate is used as a `core` replacement - see the doc comment on
+//! `future::from_generator` for why that path currently only works as a
+//! hand-callable API, not as something `.await` is wired through.
+//!
+//!
+//! As mentioned, the libstd version of the TLS-based functions use a
+//! thread-local variable, which is only supported in rust's libstd through
+//! the `thread_local!` macro - which doesn't exist in libcore. There is,
+//! however, an alternative: The (unstable) `#[thread_local]` attribute, which
+//! uses ELF TLS. Note that ELF TLS is not portable to all targets - it needs
+//! to be supported by the OS, the loader, etc...
 //!
 //! Here's a small example of the thread_local attribute in action:
 //!
@@ -46,6 +58,23 @@
 //! `poll_with_tls_context`/`from_generator` functions, but replacing the
 //! `thread_local!` macro with a `#[thread_local]` macro. Ez pz.
 //!
+//! # Context storage
+//!
+//! The TLS-based path (enabled with the `legacy-tls` feature, for toolchains
+//! that still need it) doesn't hardcode ELF TLS as its only storage
+//! mechanism. `future::set_task_context`/`future::get_task_context` are
+//! written against a small internal `ContextSlot` trait, with the concrete
+//! backend chosen by feature flag:
+//!
+//! - the `#[thread_local]` ELF-TLS cell (the default);
+//! - `unsafe-single-thread`, a plain global for targets the embedder
+//!   guarantees will only ever run on one core;
+//! - `embedder-context`, where the downstream crate supplies
+//!   `__core_futures_tls_get`/`__core_futures_tls_set` hooks and maps the
+//!   slot onto its own per-thread (or per-task) storage - for targets with
+//!   neither ELF TLS nor a single-core guarantee, such as SGX enclaves or
+//!   bare-metal/RTOS targets.
+//!
 //! # Wrapping libcore
 //!
 //! This trick is nice, but compiling a custom libcore is fastidious. Instead,
@@ -68,12 +97,21 @@
 //!
 //! # Closing thoughts
 //!
-//! While this crate still uses TLS, it should be possible to create a version
-//! that stores the thread local context in a global for single-threaded systems
-//! such as microcontrollers. This is left as an exercise to the reader.
+//! The `ResumeTy`/`get_context` path (see "Why" above) still needs a real
+//! answer for toolchains that require it: as things stand, making `.await`
+//! route through it as a genuine `core` replacement would need
+//! `#![no_core]` plus reimplementing every other lang item `core` provides,
+//! which is a much bigger undertaking than this crate currently attempts.
+//! This is left as an exercise to the reader.
 
 #![no_std]
-#![feature(thread_local, generator_trait, optin_builtin_traits)]
+#![feature(
+    thread_local,
+    generator_trait,
+    negative_impls,
+    const_fn_trait_bound,
+    into_future
+)]
 
 pub mod future;
 pub use core::*;